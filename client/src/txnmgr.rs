@@ -3,13 +3,29 @@
 //!
 #![warn(missing_docs)]
 
-use bitcoin::Txid;
+use bitcoin::{OutPoint, Txid};
 use core::time;
 use std::fmt::Debug;
 use thiserror::Error;
 
+use nakamoto_common::block::Height;
+
 use crate::handle;
 
+/// Bit in `sequence` that, when set, disables BIP68 relative-locktime semantics for
+/// the input entirely.
+const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+/// Bit in `sequence` that selects a time-based (vs block-height-based) relative
+/// locktime.
+const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+/// Mask over the low bits of `sequence` holding the relative-locktime value.
+const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000_ffff;
+/// Granularity, in seconds, of a time-based relative locktime (BIP68).
+const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 512;
+
+/// Identifies the peer a transaction event came from or was sent to.
+pub type PeerId = std::net::SocketAddr;
+
 /// The status of a transaction.
 #[derive(Clone, Debug)]
 pub enum Event {
@@ -27,6 +43,94 @@ pub enum Event {
         /// Transaction hash.
         txid: Txid,
     },
+    /// A peer rejected the transaction and will not relay it further.
+    Rejected {
+        /// Transaction hash.
+        txid: Txid,
+        /// Why the peer rejected the transaction.
+        reason: RejectReason,
+        /// The peer-supplied, free-text detail accompanying the rejection (e.g. the
+        /// specific fee/vsize figures for an insufficient-fee rejection).
+        message: String,
+        /// The peer that sent the rejection.
+        peer: PeerId,
+    },
+    /// The transaction hasn't been re-announced by any peer in a while, and may have
+    /// been dropped from mempools without ever confirming.
+    Stale {
+        /// Transaction hash.
+        txid: Txid,
+        /// How long it's been since the transaction was last seen.
+        last_seen: time::Duration,
+    },
+}
+
+/// Reason a peer gave for rejecting a transaction, per the BIP61 `reject` message
+/// codes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The message could not be parsed (`0x01`).
+    Malformed,
+    /// The transaction was invalid (`0x10`).
+    Invalid,
+    /// The peer's client version is no longer supported (`0x11`).
+    Obsolete,
+    /// The transaction double-spends an already-relayed transaction (`0x12`).
+    Duplicate,
+    /// The transaction does not meet the peer's relay policy (`0x40`).
+    Nonstandard,
+    /// The transaction creates dust outputs (`0x41`).
+    Dust,
+    /// The transaction's fee is too low (`0x42`).
+    InsufficientFee,
+    /// The transaction conflicts with a checkpoint (`0x43`).
+    Checkpoint,
+    /// A rejection code not covered by the reasons above.
+    Other(u8),
+}
+
+impl From<u8> for RejectReason {
+    fn from(code: u8) -> Self {
+        match code {
+            0x01 => Self::Malformed,
+            0x10 => Self::Invalid,
+            0x11 => Self::Obsolete,
+            0x12 => Self::Duplicate,
+            0x40 => Self::Nonstandard,
+            0x41 => Self::Dust,
+            0x42 => Self::InsufficientFee,
+            0x43 => Self::Checkpoint,
+            other => Self::Other(other),
+        }
+    }
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed => write!(fmt, "malformed"),
+            Self::Invalid => write!(fmt, "invalid"),
+            Self::Obsolete => write!(fmt, "obsolete"),
+            Self::Duplicate => write!(fmt, "duplicate"),
+            Self::Nonstandard => write!(fmt, "nonstandard"),
+            Self::Dust => write!(fmt, "dust"),
+            Self::InsufficientFee => write!(fmt, "insufficient fee"),
+            Self::Checkpoint => write!(fmt, "checkpoint"),
+            Self::Other(code) => write!(fmt, "unknown ({:#04x})", code),
+        }
+    }
+}
+
+/// Parse a BIP61-style `reject` payload received from `peer` into a terminal
+/// [`Event`] for the rejected transaction. `code` is mapped to a [`RejectReason`],
+/// while `message` is the peer's free-text explanation and is kept verbatim.
+pub fn parse_reject(txid: Txid, code: u8, message: String, peer: PeerId) -> Event {
+    Event::Rejected {
+        txid,
+        reason: RejectReason::from(code),
+        message,
+        peer,
+    }
 }
 
 /// Transaction related error.
@@ -79,6 +183,25 @@ impl std::fmt::Display for Event {
                     confirmations, txid,
                 )
             }
+            Event::Rejected {
+                txid,
+                reason,
+                message,
+                peer,
+            } => {
+                write!(
+                    fmt,
+                    "Transaction ID {} rejected by peer {}: {} ({})",
+                    txid, peer, reason, message
+                )
+            }
+            Event::Stale { txid, last_seen } => {
+                write!(
+                    fmt,
+                    "Transaction ID {} not seen announced in {:?}",
+                    txid, last_seen
+                )
+            }
         }
     }
 }
@@ -97,6 +220,178 @@ pub trait Transaction {
         txn: bitcoin::Transaction,
         duration: time::Duration,
     ) -> Result<Event, handle::Error>;
-    /// Wait for transaction to be sent to a peer.
+    /// Wait for transaction to be sent to a peer. Implementors must return as soon as
+    /// a terminal event — `Event::Accepted`, `Event::Rejected`, or `Event::Stale` — is
+    /// observed, rather than always spinning until `timeout`.
     fn wait(&self, tx_id: Txid, timeout: time::Duration) -> Result<Event, handle::Error>;
+
+    /// Height at which the output `outpoint` (i.e. the transaction that created it)
+    /// confirmed, if known.
+    fn confirmation_height(&self, outpoint: &OutPoint) -> Result<Option<Height>, handle::Error>;
+
+    /// BIP113 median-time-past as of the given height.
+    fn median_time_past(&self, height: Height) -> Result<u32, handle::Error>;
+
+    /// Whether `txn` is final under BIP68 relative-locktime rules, were it to be
+    /// included in the block following `tip`. For a version 2+ transaction, an input
+    /// with relative locktime enabled whose previous output hasn't confirmed yet makes
+    /// the transaction unspendable.
+    fn spendable_at(&self, txn: &bitcoin::Transaction, tip: Height) -> Result<bool, handle::Error> {
+        // BIP68 only applies to version 2+ transactions; peers ignore `sequence`
+        // entirely for older ones, so they're always final.
+        if txn.version < 2 {
+            return Ok(true);
+        }
+
+        let tip_mtp = self.median_time_past(tip)?;
+
+        for input in &txn.input {
+            let sequence = input.sequence;
+
+            // Relative locktime is disabled for this input; it's always mature.
+            if sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+                continue;
+            }
+            let confirmed_at = match self.confirmation_height(&input.previous_output)? {
+                Some(height) => height,
+                None => return Ok(false),
+            };
+            let locktime = sequence & SEQUENCE_LOCKTIME_MASK;
+
+            // Per BIP68/Core: `nMinHeight`/`nMinTime` are derived from the block
+            // *before* confirmation (`confirmed_at - 1`), and locks are checked for
+            // inclusion in the next block (`tip + 1`). Height-based locks thus reduce to
+            // `tip >= confirmed_at + locktime - 1`, and time-based ones to
+            // `mtp(tip) >= mtp(confirmed_at - 1) + locktime * 512`.
+            let matured = if sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+                let input_mtp = self.median_time_past(confirmed_at.saturating_sub(1))?;
+                tip_mtp >= input_mtp + locktime * SEQUENCE_LOCKTIME_GRANULARITY
+            } else {
+                tip >= (confirmed_at + locktime as Height).saturating_sub(1)
+            };
+
+            if !matured {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    struct Mock {
+        confirmations: HashMap<OutPoint, Height>,
+        mtp: HashMap<Height, u32>,
+    }
+
+    impl Transaction for Mock {
+        fn submit_transaction(
+            &mut self,
+            _txn: bitcoin::Transaction,
+            _duration: time::Duration,
+        ) -> Result<Event, handle::Error> {
+            unreachable!()
+        }
+
+        fn wait(&self, _tx_id: Txid, _timeout: time::Duration) -> Result<Event, handle::Error> {
+            unreachable!()
+        }
+
+        fn confirmation_height(&self, outpoint: &OutPoint) -> Result<Option<Height>, handle::Error> {
+            Ok(self.confirmations.get(outpoint).copied())
+        }
+
+        fn median_time_past(&self, height: Height) -> Result<u32, handle::Error> {
+            Ok(*self.mtp.get(&height).unwrap_or(&0))
+        }
+    }
+
+    fn outpoint() -> OutPoint {
+        OutPoint {
+            txid: Txid::default(),
+            vout: 0,
+        }
+    }
+
+    fn mock(confirmed_at: Height, mtp: &[(Height, u32)]) -> Mock {
+        let mut confirmations = HashMap::new();
+        confirmations.insert(outpoint(), confirmed_at);
+
+        Mock {
+            confirmations,
+            mtp: mtp.iter().copied().collect(),
+        }
+    }
+
+    fn txn_with_sequence(version: i32, sequence: u32) -> bitcoin::Transaction {
+        bitcoin::Transaction {
+            version,
+            lock_time: 0,
+            input: vec![bitcoin::TxIn {
+                previous_output: outpoint(),
+                script_sig: bitcoin::Script::new(),
+                sequence,
+                witness: bitcoin::Witness::default(),
+            }],
+            output: vec![],
+        }
+    }
+
+    #[test]
+    fn test_spendable_at_height_based() {
+        // `locktime = 1` matures one block after confirmation: height 100 itself is
+        // already enough, per BIP68's `nMinHeight = nCoinHeight + locktime - 1`.
+        let txn = txn_with_sequence(2, 1);
+        let store = mock(100, &[]);
+
+        assert!(!store.spendable_at(&txn, 99).unwrap());
+        assert!(store.spendable_at(&txn, 100).unwrap());
+    }
+
+    #[test]
+    fn test_spendable_at_time_based() {
+        // `locktime = 1` in 512-second units, type flag set.
+        let sequence = SEQUENCE_LOCKTIME_TYPE_FLAG | 1;
+        let txn = txn_with_sequence(2, sequence);
+        // The BIP68 baseline is the MTP of the block *before* confirmation (99), not
+        // the confirming block (100) itself.
+        let store = mock(100, &[(99, 1_000), (200, 1_000 + 511), (201, 1_000 + 512)]);
+
+        assert!(!store.spendable_at(&txn, 200).unwrap());
+        assert!(store.spendable_at(&txn, 201).unwrap());
+    }
+
+    #[test]
+    fn test_spendable_at_disable_flag() {
+        let txn = txn_with_sequence(2, SEQUENCE_LOCKTIME_DISABLE_FLAG);
+        let store = mock(100, &[]);
+
+        assert!(store.spendable_at(&txn, 0).unwrap());
+    }
+
+    #[test]
+    fn test_spendable_at_pre_bip68_version_always_final() {
+        // Version 1 transactions are final regardless of `sequence`.
+        let txn = txn_with_sequence(1, 5);
+        let store = mock(100, &[]);
+
+        assert!(store.spendable_at(&txn, 0).unwrap());
+    }
+
+    #[test]
+    fn test_reject_reason_from_code() {
+        assert_eq!(RejectReason::from(0x01), RejectReason::Malformed);
+        assert_eq!(RejectReason::from(0x10), RejectReason::Invalid);
+        assert_eq!(RejectReason::from(0x11), RejectReason::Obsolete);
+        assert_eq!(RejectReason::from(0x12), RejectReason::Duplicate);
+        assert_eq!(RejectReason::from(0x40), RejectReason::Nonstandard);
+        assert_eq!(RejectReason::from(0x41), RejectReason::Dust);
+        assert_eq!(RejectReason::from(0x42), RejectReason::InsufficientFee);
+        assert_eq!(RejectReason::from(0x43), RejectReason::Checkpoint);
+        assert_eq!(RejectReason::from(0x99), RejectReason::Other(0x99));
+    }
 }
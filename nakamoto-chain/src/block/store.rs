@@ -4,9 +4,11 @@ use crate::blocktree::Height;
 
 use bitcoin::blockdata::block::BlockHeader;
 use bitcoin::consensus::encode;
+use bitcoin::BlockHash;
 use nonempty::NonEmpty;
 use thiserror::Error;
 
+use std::cell::Cell;
 use std::fmt;
 
 #[derive(Debug, Error)]
@@ -28,6 +30,24 @@ pub trait Store: fmt::Debug {
     fn put<I: Iterator<Item = BlockHeader>>(&mut self, headers: I) -> Result<Height, Error>;
     /// Get the block at the given height.
     fn get(&self, height: Height) -> Result<BlockHeader, Error>;
+    /// Get the header and height matching the given block hash, if any is stored.
+    fn get_by_hash(&self, hash: BlockHash) -> Result<Option<(Height, BlockHeader)>, Error>;
+    /// Compute the BIP113 median-time-past as of the given height, using the `time`
+    /// field of the last eleven headers ending at `height` (fewer near genesis). For
+    /// an even number of headers, the lower-middle element is returned.
+    fn median_time_past(&self, height: Height) -> Result<u32, Error> {
+        let start = height.saturating_sub(10);
+        let mut times = (start..=height)
+            .map(|h| self.get(h).map(|header| header.time))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        times.sort_unstable();
+
+        let mid = times.len() / 2;
+        let index = if times.len() % 2 == 0 { mid - 1 } else { mid };
+
+        Ok(times[index])
+    }
     /// Rollback the chain to the given height.
     fn rollback(&mut self, height: Height) -> Result<(), Error>;
     /// Synchronize the changes to disk.
@@ -38,6 +58,60 @@ pub trait Store: fmt::Debug {
     fn len(&self) -> Result<usize, Error>;
 }
 
+/// A block header bundled with its block hash, which is computed lazily and memoized
+/// so that repeated lookups never re-hash the same header.
+#[derive(Debug)]
+pub struct IndexedHeader {
+    header: BlockHeader,
+    hash: Cell<Option<BlockHash>>,
+}
+
+impl IndexedHeader {
+    /// Wrap a header, without computing its hash yet.
+    pub fn new(header: BlockHeader) -> Self {
+        Self {
+            header,
+            hash: Cell::new(None),
+        }
+    }
+
+    /// The wrapped header.
+    pub fn header(&self) -> BlockHeader {
+        self.header
+    }
+
+    /// The header's block hash, computing and caching it on the first call.
+    pub fn hash(&self) -> BlockHash {
+        if let Some(hash) = self.hash.get() {
+            return hash;
+        }
+        let hash = self.header.block_hash();
+        self.hash.set(Some(hash));
+        hash
+    }
+}
+
+impl Clone for IndexedHeader {
+    fn clone(&self) -> Self {
+        Self {
+            header: self.header,
+            hash: Cell::new(self.hash.get()),
+        }
+    }
+}
+
+impl From<BlockHeader> for IndexedHeader {
+    fn from(header: BlockHeader) -> Self {
+        Self::new(header)
+    }
+}
+
+impl PartialEq for IndexedHeader {
+    fn eq(&self, other: &Self) -> bool {
+        self.header == other.header
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Memory(NonEmpty<BlockHeader>);
 
@@ -70,6 +144,17 @@ impl Store for Memory {
         }
     }
 
+    /// Get the header and height matching the given block hash, if any is stored.
+    fn get_by_hash(&self, hash: BlockHash) -> Result<Option<(Height, BlockHeader)>, Error> {
+        Ok(self.0.iter().enumerate().find_map(|(i, h)| {
+            if h.block_hash() == hash {
+                Some((i as Height, *h))
+            } else {
+                None
+            }
+        }))
+    }
+
     /// Rollback the chain to the given height.
     fn rollback(&mut self, height: Height) -> Result<(), Error> {
         match height {
@@ -102,19 +187,27 @@ impl Store for Memory {
 }
 
 pub mod io {
-    use super::{Error, Store};
+    use super::{Error, IndexedHeader, Store};
     use crate::blocktree::Height;
 
     use bitcoin::blockdata::block::BlockHeader;
     use bitcoin::consensus::encode::{Decodable, Encodable};
+    use bitcoin::BlockHash;
+
+    use lru::LruCache;
 
+    use std::cell::RefCell;
+    use std::collections::HashMap;
     use std::fs::{self, File};
     use std::io::{self, Read, Seek, Write};
     use std::iter;
+    use std::num::NonZeroUsize;
     use std::path::Path;
 
     // Size in bytes of a block header.
     const HEADER_SIZE: usize = 80;
+    // Default number of headers kept in the in-memory read cache.
+    const DEFAULT_CACHE_SIZE: usize = 2048;
 
     /// Append a block to the end of the file.
     fn put<S: Seek + Write, I: Iterator<Item = BlockHeader>>(
@@ -138,6 +231,30 @@ pub mod io {
         BlockHeader::consensus_decode(&buf[..]).map_err(Error::from)
     }
 
+    // Rebuild the block-hash index by streaming the file once. Used on `open`, since the
+    // index itself isn't persisted to disk.
+    fn build_index(file: &File) -> Result<HashMap<BlockHash, Height>, Error> {
+        let mut index = HashMap::new();
+
+        for result in (Iter {
+            height: 0,
+            file: file.try_clone()?,
+        }) {
+            let (height, header) = result?;
+            index.insert(header.block_hash(), height);
+        }
+        Ok(index)
+    }
+
+    impl From<Error> for io::Error {
+        fn from(err: Error) -> Self {
+            match err {
+                Error::Io(err) => err,
+                err => io::Error::new(io::ErrorKind::Other, err.to_string()),
+            }
+        }
+    }
+
     /// An iterator over block headers in a file.
     pub struct Iter {
         height: Height,
@@ -169,16 +286,33 @@ pub mod io {
     #[derive(Debug)]
     pub struct FileStore {
         file: File,
+        // Read cache, keyed by height. Lets repeated `get`s over the same tip region
+        // (header validation, locator building) skip the `seek` + `read_exact` round-trip.
+        cache: RefCell<LruCache<Height, IndexedHeader>>,
+        // In-memory block-hash index, rebuilt from the file on `open`. Lets `get_by_hash`
+        // answer without an `O(n)` scan over the whole file.
+        index: RefCell<HashMap<BlockHash, Height>>,
     }
 
     impl FileStore {
         pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
-            fs::OpenOptions::new()
+            Self::with_cache(path, DEFAULT_CACHE_SIZE)
+        }
+
+        /// Open a file store with a read cache of the given capacity.
+        pub fn with_cache<P: AsRef<Path>>(path: P, cap: usize) -> io::Result<Self> {
+            let file = fs::OpenOptions::new()
                 .create(true)
                 .read(true)
                 .append(true)
-                .open(path)
-                .map(|file| Self { file })
+                .open(path)?;
+            let index = build_index(&file)?;
+
+            Ok(Self {
+                file,
+                cache: RefCell::new(LruCache::new(cache_capacity(cap))),
+                index: RefCell::new(index),
+            })
         }
 
         pub fn create<P: AsRef<Path>>(path: P, genesis: BlockHeader) -> Result<Self, Error> {
@@ -190,23 +324,68 @@ pub mod io {
 
             put(&mut file, iter::once(genesis))?;
 
-            Ok(Self { file })
+            let mut index = HashMap::new();
+            index.insert(genesis.block_hash(), 0);
+
+            Ok(Self {
+                file,
+                cache: RefCell::new(LruCache::new(cache_capacity(DEFAULT_CACHE_SIZE))),
+                index: RefCell::new(index),
+            })
         }
     }
 
+    // `LruCache` requires a non-zero capacity; fall back to a single entry rather than
+    // panicking on a caller-supplied `0`.
+    fn cache_capacity(cap: usize) -> NonZeroUsize {
+        NonZeroUsize::new(cap).unwrap_or(NonZeroUsize::new(1).unwrap())
+    }
+
     impl Store for FileStore {
         /// Append a block to the end of the file.
         fn put<I: Iterator<Item = BlockHeader>>(&mut self, headers: I) -> Result<Height, Error> {
-            self::put(&mut self.file, headers)
+            let start = self.len()? as Height;
+            let headers: Vec<_> = headers.collect();
+            let height = self::put(&mut self.file, headers.iter().cloned())?;
+
+            let mut cache = self.cache.borrow_mut();
+            let mut index = self.index.borrow_mut();
+
+            for (i, header) in headers.into_iter().enumerate() {
+                let h = start + i as Height;
+                let indexed = IndexedHeader::new(header);
+
+                index.insert(indexed.hash(), h);
+                cache.put(h, indexed);
+            }
+            Ok(height)
         }
 
         /// Get the block at the given height. Returns `io::ErrorKind::UnexpectedEof` if
         /// the height is not found.
         fn get(&self, height: Height) -> Result<BlockHeader, Error> {
+            if let Some(indexed) = self.cache.borrow_mut().get(&height) {
+                return Ok(indexed.header());
+            }
+
             // Clone so this function doesn't have to take a `&mut self`.
             let mut file = self.file.try_clone()?;
+            let header = get(&mut file, height)?;
+
+            self.cache
+                .borrow_mut()
+                .put(height, IndexedHeader::new(header));
 
-            get(&mut file, height)
+            Ok(header)
+        }
+
+        /// Get the header and height matching the given block hash, if any is stored.
+        fn get_by_hash(&self, hash: BlockHash) -> Result<Option<(Height, BlockHeader)>, Error> {
+            let height = match self.index.borrow().get(&hash) {
+                Some(height) => *height,
+                None => return Ok(None),
+            };
+            Ok(Some((height, self.get(height)?)))
         }
 
         /// Rollback the chain to the given height. Behavior is undefined if  the given
@@ -214,7 +393,24 @@ pub mod io {
         fn rollback(&mut self, height: Height) -> Result<(), Error> {
             self.file
                 .set_len((height + 1) * HEADER_SIZE as u64)
-                .map_err(Error::from)
+                .map_err(Error::from)?;
+
+            // Evict everything past the new tip so a stale header can never be served
+            // after a reorg.
+            let mut cache = self.cache.borrow_mut();
+            let stale: Vec<Height> = cache
+                .iter()
+                .map(|(h, _)| *h)
+                .filter(|h| *h > height)
+                .collect();
+
+            for h in stale {
+                cache.pop(&h);
+            }
+
+            self.index.borrow_mut().retain(|_, h| *h <= height);
+
+            Ok(())
         }
 
         /// Flush changes to disk.
@@ -374,5 +570,137 @@ pub mod io {
                 assert_eq!(header, headers[height as usize]);
             }
         }
+
+        #[test]
+        fn test_cache_eviction_on_rollback() {
+            let tmp = tempfile::tempdir().unwrap();
+            let mut store = FileStore::with_cache(tmp.path().join("headers.db"), 16).unwrap();
+
+            let count = 8;
+            let header = BlockHeader {
+                version: 1,
+                prev_blockhash: Default::default(),
+                merkle_root: Default::default(),
+                bits: 0x2ffffff,
+                time: 1842918273,
+                nonce: 0,
+            };
+            let iter = (0..count).map(|i| BlockHeader { nonce: i, ..header });
+            let headers = iter.clone().collect::<Vec<_>>();
+
+            store.put(iter).unwrap();
+
+            // Warm the cache for every height.
+            for i in 0..count {
+                assert_eq!(store.get(i as Height).unwrap(), headers[i as usize]);
+            }
+
+            let h = count as Height / 2;
+            store.rollback(h).unwrap();
+
+            // Heights past the rollback point must never be served from the cache,
+            // even though they were cached before the rollback.
+            assert!(store.get(h + 1).is_err());
+
+            // Overwrite the tail with different headers and make sure the stale,
+            // pre-rollback cache entries don't leak through.
+            let overwrite = BlockHeader {
+                nonce: 909090,
+                ..header
+            };
+            let height = store.put(iter::once(overwrite)).unwrap();
+
+            assert_eq!(height, h + 1);
+            assert_eq!(store.get(height).unwrap(), overwrite);
+            assert_ne!(store.get(height).unwrap(), headers[height as usize]);
+        }
+
+        #[test]
+        fn test_get_by_hash() {
+            let tmp = tempfile::tempdir().unwrap();
+            let path = tmp.path().join("headers.db");
+            let mut store = FileStore::open(&path).unwrap();
+
+            let count = 8;
+            let header = BlockHeader {
+                version: 1,
+                prev_blockhash: Default::default(),
+                merkle_root: Default::default(),
+                bits: 0x2ffffff,
+                time: 1842918273,
+                nonce: 0,
+            };
+            let iter = (0..count).map(|i| BlockHeader { nonce: i, ..header });
+            let headers = iter.clone().collect::<Vec<_>>();
+
+            store.put(iter).unwrap();
+
+            for (i, h) in headers.iter().enumerate() {
+                assert_eq!(
+                    store.get_by_hash(h.block_hash()).unwrap(),
+                    Some((i as Height, *h))
+                );
+            }
+            assert_eq!(
+                store.get_by_hash(BlockHeader { nonce: 999, ..header }.block_hash()).unwrap(),
+                None
+            );
+
+            // Rolling back drops the index entries for the heights beyond the new tip.
+            let h = count as Height / 2;
+            store.rollback(h).unwrap();
+
+            for header in &headers[h as usize + 1..] {
+                assert_eq!(store.get_by_hash(header.block_hash()).unwrap(), None);
+            }
+            for header in &headers[..=h as usize] {
+                assert!(store.get_by_hash(header.block_hash()).unwrap().is_some());
+            }
+
+            // Re-opening the store rebuilds the index from the file alone.
+            drop(store);
+            let reopened = FileStore::open(&path).unwrap();
+
+            for header in &headers[..=h as usize] {
+                assert!(reopened.get_by_hash(header.block_hash()).unwrap().is_some());
+            }
+        }
+
+        #[test]
+        fn test_median_time_past() {
+            let tmp = tempfile::tempdir().unwrap();
+            let mut store = FileStore::open(tmp.path().join("headers.db")).unwrap();
+
+            let header = BlockHeader {
+                version: 1,
+                prev_blockhash: Default::default(),
+                merkle_root: Default::default(),
+                bits: 0x2ffffff,
+                time: 1_600_000_000,
+                nonce: 0,
+            };
+            // Eleven headers with distinct times, stored out of time order.
+            let times = [5u32, 1, 4, 2, 3, 9, 6, 8, 7, 0, 10];
+            let iter = times
+                .iter()
+                .map(|&t| BlockHeader { time: header.time + t, ..header });
+
+            store.put(iter).unwrap();
+
+            // With all eleven headers available, the median is the 6th smallest.
+            assert_eq!(
+                store.median_time_past(10).unwrap(),
+                header.time + 5
+            );
+
+            // Near genesis, fewer headers are available but the median is still well
+            // defined.
+            assert_eq!(store.median_time_past(0).unwrap(), header.time + 5);
+
+            // A partial, even-sized window (heights 0..=5, offsets [5, 1, 4, 2, 3, 9]
+            // sorted to [1, 2, 3, 4, 5, 9]) pins down the tie-breaking convention: the
+            // lower of the two middle elements.
+            assert_eq!(store.median_time_past(5).unwrap(), header.time + 3);
+        }
     }
 }
\ No newline at end of file